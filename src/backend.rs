@@ -0,0 +1,128 @@
+use std::{
+    collections::BTreeMap,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use clap::ValueEnum;
+
+use crate::session::SessionSource;
+
+/// Number of points each backend occupies on the consistent hash ring. More replicas smooth out
+/// the distribution of sessions across backends at the cost of a larger ring to search.
+const VIRTUAL_NODES_PER_BACKEND: usize = 100;
+
+/// How traffic is distributed across the backends in a [BackendPool].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BalanceMode {
+    /// Hash the session's source address/port onto a ring so it always lands on the same backend.
+    ConsistentHash,
+    /// Cycle through backends in order, independent of the session's source.
+    RoundRobin,
+}
+
+/// A fixed pool of upstream backends that new [crate::session::Session]s are spread across.
+#[derive(Debug)]
+pub struct BackendPool {
+    backends: Vec<SocketAddr>,
+    mode: BalanceMode,
+    ring: BTreeMap<u64, usize>,
+    next: AtomicUsize,
+}
+
+impl BackendPool {
+    /// Builds a pool over `backends`, distributed according to `mode`. Panics if `backends` is empty.
+    pub fn new(backends: Vec<SocketAddr>, mode: BalanceMode) -> Self {
+        assert!(!backends.is_empty(), "a backend pool requires at least one destination");
+
+        let mut ring = BTreeMap::new();
+        for (index, backend) in backends.iter().enumerate() {
+            for replica in 0..VIRTUAL_NODES_PER_BACKEND {
+                ring.insert(hash_key(&(backend, replica)), index);
+            }
+        }
+
+        BackendPool {
+            backends,
+            mode,
+            ring,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Selects the backend that traffic from `source` should be proxied to. Under
+    /// [BalanceMode::ConsistentHash] a given `source` always resolves to the same backend for the
+    /// lifetime of the pool; under [BalanceMode::RoundRobin] backends are cycled through in order.
+    pub fn select(&self, source: &SessionSource) -> SocketAddr {
+        let index = match self.mode {
+            BalanceMode::ConsistentHash => {
+                let key = hash_key(&(source.address, source.port));
+                *self
+                    .ring
+                    .range(key..)
+                    .next()
+                    .map(|(_, index)| index)
+                    .unwrap_or_else(|| {
+                        self.ring
+                            .values()
+                            .next()
+                            .expect("ring is non-empty since backends is non-empty")
+                    })
+            }
+            BalanceMode::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % self.backends.len(),
+        };
+
+        self.backends[index]
+    }
+}
+
+fn hash_key<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    fn backends() -> Vec<SocketAddr> {
+        vec![
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 9000),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 9000),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)), 9000),
+        ]
+    }
+
+    fn source(port: u16) -> SessionSource {
+        SessionSource {
+            address: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
+            port,
+        }
+    }
+
+    #[test]
+    fn consistent_hash_sticks_the_same_source_to_the_same_backend() {
+        let pool = BackendPool::new(backends(), BalanceMode::ConsistentHash);
+        let source = source(4321);
+
+        let first = pool.select(&source);
+        for _ in 0..10 {
+            assert_eq!(pool.select(&source), first);
+        }
+    }
+
+    #[test]
+    fn round_robin_cycles_through_every_backend_in_order() {
+        let pool = BackendPool::new(backends(), BalanceMode::RoundRobin);
+        let source = source(4321);
+
+        let selections: Vec<_> = (0..backends().len() * 2).map(|_| pool.select(&source)).collect();
+
+        assert_eq!(selections, [backends(), backends()].concat());
+    }
+}
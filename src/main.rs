@@ -2,38 +2,79 @@
 use std::io::ErrorKind;
 use std::{
     io,
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
+use backend::{BackendPool, BalanceMode};
 use clap::Parser;
 #[cfg(not(debug_assertions))]
 use listenfd::ListenFd;
-#[cfg(not(debug_assertions))]
-use log::warn;
+use log::{error, info, warn};
+use metrics::Metrics;
 use primary_tasks::{rx_loop, tx_loop};
 use session::SessionReply;
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::{
+    net::UdpSocket,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch},
+    time::timeout,
+};
+use tunnel::Tunnel;
 
+mod backend;
 mod error_util;
 mod log_config;
+mod metrics;
 mod primary_tasks;
+mod proxy_protocol;
 mod session;
+mod systemd_notify;
+mod tunnel;
 
 #[derive(Parser, Debug)]
 struct Args {
-    /// The destination port to proxy traffic to
-    #[arg(short = 'p', long)]
-    destination_port: u16,
     /// The address to bind to to send proxy traffic from
     #[arg(short = 's', long, default_value_t = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))]
     source_address: IpAddr,
-    /// The destination address to send proxy traffic to
-    #[arg(short = 'd', long, default_value_t = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))]
-    destination_address: IpAddr,
+    /// A destination address:port to proxy traffic to. May be repeated to load-balance across
+    /// multiple backends, e.g. `-d 10.0.0.1:9000 -d 10.0.0.2:9000`
+    #[arg(short = 'd', long = "destination", required = true)]
+    destinations: Vec<SocketAddr>,
+    /// How sessions are distributed across multiple `--destination` backends
+    #[arg(long, value_enum, default_value_t = BalanceMode::ConsistentHash)]
+    balance_mode: BalanceMode,
+    /// Prepend a PROXY protocol v2 header to datagrams sent to the backend, so it can recover
+    /// the original client address instead of only seeing this proxy's address
+    #[arg(long)]
+    proxy_protocol: bool,
+    /// Path to a 32-byte pre-shared key file enabling an encrypted XChaCha20-Poly1305 tunnel on the
+    /// backend-facing leg, i.e. traffic sent to/received from `--destination`. Falls back to the
+    /// `UDP_PROXY_DESTINATION_TUNNEL_KEY` environment variable if unset; if neither is set, that
+    /// leg is not tunneled. Set this on the instance acting as the tunnel's ingress
+    #[arg(long)]
+    destination_tunnel_key_file: Option<PathBuf>,
+    /// Path to a 32-byte pre-shared key file enabling an encrypted XChaCha20-Poly1305 tunnel on the
+    /// client-facing leg, i.e. traffic sent to/received from the original source. Falls back to the
+    /// `UDP_PROXY_SOURCE_TUNNEL_KEY` environment variable if unset; if neither is set, that leg is
+    /// not tunneled. Set this on the instance acting as the tunnel's egress
+    #[arg(long)]
+    source_tunnel_key_file: Option<PathBuf>,
     /// How many seconds sessions should be cached before expiring
     #[arg(short = 't', long, default_value_t = 60)]
     session_timeout: u64,
+    /// Maximum number of datagrams that may be queued per channel before new ones are dropped. Must
+    /// be at least 1, since tokio's bounded channels panic on a zero-sized buffer
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(usize).range(1..))]
+    session_queue_size: usize,
+    /// Address to serve Prometheus-format metrics on (e.g. 127.0.0.1:9090). If unset, metrics are not exposed
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// Seconds to wait for in-flight sessions to drain after receiving SIGTERM/SIGINT before exiting
+    #[arg(long, default_value_t = 5)]
+    shutdown_grace_period: u64,
 }
 
 const MAX_UDP_PACKET_SIZE: u16 = u16::MAX;
@@ -67,12 +108,92 @@ async fn main() -> io::Result<()> {
     std_source_socket.set_nonblocking(true)?;
 
     let source_socket = Arc::new(UdpSocket::from_std(std_source_socket)?);
-    let (reply_channel_tx, reply_channel_rx) = mpsc::unbounded_channel::<SessionReply>();
+    let (reply_channel_tx, reply_channel_rx) = mpsc::channel::<SessionReply>(args.session_queue_size);
+    let backend_pool = Arc::new(BackendPool::new(args.destinations.clone(), args.balance_mode));
+    let destination_tunnel = Tunnel::from_args(
+        &args.destination_tunnel_key_file,
+        tunnel::DESTINATION_TUNNEL_KEY_ENV_VAR,
+    )?
+    .map(Arc::new);
+    let source_tunnel = Tunnel::from_args(&args.source_tunnel_key_file, tunnel::SOURCE_TUNNEL_KEY_ENV_VAR)?
+        .map(Arc::new);
+    let metrics = Metrics::new();
+
+    if let Some(metrics_addr) = args.metrics_addr {
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_addr, metrics).await {
+                error!("Metrics server failed: {:?}", err);
+            }
+        });
+    }
+
+    let shutdown_grace_period = Duration::from_secs(args.shutdown_grace_period);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let mut rx_task = tokio::spawn(rx_loop(
+        args,
+        backend_pool,
+        destination_tunnel,
+        source_tunnel.clone(),
+        metrics.clone(),
+        reply_channel_tx,
+        source_socket.clone(),
+        shutdown_rx,
+    ));
+    let mut tx_task = tokio::spawn(tx_loop(
+        reply_channel_rx,
+        source_socket.clone(),
+        source_tunnel,
+        metrics,
+    ));
 
-    let rx_task = tokio::spawn(rx_loop(args, reply_channel_tx, source_socket.clone()));
-    let tx_task = tokio::spawn(tx_loop(reply_channel_rx, source_socket.clone()));
+    // Both loops are spawned and the source socket is live, so tell systemd we're ready. This is a
+    // no-op if the proxy wasn't started under systemd (e.g. when NOTIFY_SOCKET isn't set).
+    systemd_notify::notify("READY=1")?;
+    if let Some(interval) = systemd_notify::watchdog_interval() {
+        tokio::spawn(watchdog_loop(interval));
+    }
 
-    rx_task.await??;
-    tx_task.await??;
-    Ok(())
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM, draining sessions before shutdown"),
+        _ = sigint.recv() => info!("Received SIGINT, draining sessions before shutdown"),
+        result = &mut rx_task => return result?,
+        result = &mut tx_task => return result?,
+    }
+
+    // Stop accepting new sessions and let existing ones wind down their tx/rx loops promptly
+    // instead of waiting out their full session timeout.
+    let _ = shutdown_tx.send(true);
+    match timeout(shutdown_grace_period, async {
+        rx_task.await??;
+        tx_task.await??;
+        Ok::<(), io::Error>(())
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => {
+            warn!(
+                "Sessions did not drain within the {}s grace period; exiting anyway",
+                shutdown_grace_period.as_secs()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Periodically notifies systemd that this process is still alive, for units configured with
+/// `WatchdogSec=`. Pings at `interval`, which [systemd_notify::watchdog_interval] derives as half
+/// of the configured timeout per sd_notify(3) convention.
+async fn watchdog_loop(interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = systemd_notify::notify("WATCHDOG=1") {
+            error!("Failed to send watchdog notification: {:?}", err);
+        }
+    }
 }
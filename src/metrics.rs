@@ -0,0 +1,92 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use log::info;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Process-wide counters tracking traffic flowing through the proxy. Shared across [crate::primary_tasks]
+/// and every [crate::session::Session], and rendered in Prometheus text exposition format by [serve].
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub active_sessions: AtomicU64,
+    pub packets_forwarded_to_backend: AtomicU64,
+    pub bytes_forwarded_to_backend: AtomicU64,
+    pub packets_forwarded_to_client: AtomicU64,
+    pub bytes_forwarded_to_client: AtomicU64,
+    pub packets_dropped: AtomicU64,
+    pub sessions_expired: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Metrics::default())
+    }
+
+    fn render(&self) -> String {
+        format!(
+            "# HELP udp_proxy_active_sessions Number of sessions currently open\n\
+             # TYPE udp_proxy_active_sessions gauge\n\
+             udp_proxy_active_sessions {}\n\
+             # HELP udp_proxy_packets_forwarded_to_backend_total Datagrams forwarded from the source to a backend\n\
+             # TYPE udp_proxy_packets_forwarded_to_backend_total counter\n\
+             udp_proxy_packets_forwarded_to_backend_total {}\n\
+             # HELP udp_proxy_bytes_forwarded_to_backend_total Bytes forwarded from the source to a backend\n\
+             # TYPE udp_proxy_bytes_forwarded_to_backend_total counter\n\
+             udp_proxy_bytes_forwarded_to_backend_total {}\n\
+             # HELP udp_proxy_packets_forwarded_to_client_total Datagrams forwarded from a backend back to the source\n\
+             # TYPE udp_proxy_packets_forwarded_to_client_total counter\n\
+             udp_proxy_packets_forwarded_to_client_total {}\n\
+             # HELP udp_proxy_bytes_forwarded_to_client_total Bytes forwarded from a backend back to the source\n\
+             # TYPE udp_proxy_bytes_forwarded_to_client_total counter\n\
+             udp_proxy_bytes_forwarded_to_client_total {}\n\
+             # HELP udp_proxy_packets_dropped_total Packets dropped because their session was closed or its queue was full\n\
+             # TYPE udp_proxy_packets_dropped_total counter\n\
+             udp_proxy_packets_dropped_total {}\n\
+             # HELP udp_proxy_sessions_expired_total Sessions closed due to session_timeout inactivity\n\
+             # TYPE udp_proxy_sessions_expired_total counter\n\
+             udp_proxy_sessions_expired_total {}\n",
+            self.active_sessions.load(Ordering::Relaxed),
+            self.packets_forwarded_to_backend.load(Ordering::Relaxed),
+            self.bytes_forwarded_to_backend.load(Ordering::Relaxed),
+            self.packets_forwarded_to_client.load(Ordering::Relaxed),
+            self.bytes_forwarded_to_client.load(Ordering::Relaxed),
+            self.packets_dropped.load(Ordering::Relaxed),
+            self.sessions_expired.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Serves `metrics` in Prometheus text exposition format over plain HTTP at `addr` until the
+/// process exits or the listener errors. Every request receives the same document regardless of
+/// path or method; this is a bare-bones exporter, not a general purpose HTTP server.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics at http://{addr}/metrics");
+
+    loop {
+        let (mut stream, _peer) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // The request is discarded; this exporter always serves the same document.
+            let mut discard = [0u8; 1024];
+            let _ = stream.read(&mut discard).await;
+
+            let body = metrics.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
@@ -1,26 +1,39 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
     io,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use log::{error, info};
+use log::{error, info, warn};
 use tokio::{
     net::UdpSocket,
     sync::{
-        mpsc::{self, UnboundedSender},
-        RwLock,
+        mpsc::{self, error::TrySendError, Sender},
+        watch, RwLock,
     },
+    time::timeout,
 };
 
 use crate::{
+    backend::BackendPool,
     error_util::{handle_io_error, ErrorAction},
+    metrics::Metrics,
     session::{Session, SessionReply, SessionSource},
+    tunnel::Tunnel,
     Args, MAX_UDP_PACKET_SIZE,
 };
 
-type SessionChannel = UnboundedSender<Vec<u8>>;
-type SessionCache = HashMap<SessionSource, (SessionChannel, Arc<Session>)>;
+/// How many drops are accumulated on a session's queue before another warning is logged for it,
+/// so a sustained flood doesn't spam the log once per dropped datagram.
+const DROP_LOG_INTERVAL: u64 = 100;
+
+type SessionChannel = Sender<Vec<u8>>;
+type DropCounter = Arc<AtomicU64>;
+type SessionCache = HashMap<SessionSource, (SessionChannel, Arc<Session>, DropCounter)>;
 
 /// Loops infinitely over the `rx_socket` to recieve traffic from the original source of the proxy.
 ///
@@ -28,73 +41,179 @@ type SessionCache = HashMap<SessionSource, (SessionChannel, Arc<Session>)>;
 /// tx/rx loop tasks are spawned to proxy traffic for that session to and from the destination. If a [Session]
 /// does not recieve traffic for [Args::session_timeout] seconds, it will close its tasks and a new one will
 /// need to be created if any traffic resumes from it.
+///
+/// Once `shutdown` is signaled, this loop stops creating new sessions but keeps reading `rx_socket`
+/// and routing datagrams to already-active sessions for up to `Args::shutdown_grace_period`, so they
+/// can actually drain instead of merely flushing whatever was already buffered. Sessions themselves
+/// are independent tasks that drain for their own grace period once `shutdown` fires, unless they time
+/// out naturally first.
 pub async fn rx_loop(
     args: Args,
-    reply_channel_tx: UnboundedSender<SessionReply>,
+    backend_pool: Arc<BackendPool>,
+    destination_tunnel: Option<Arc<Tunnel>>,
+    source_tunnel: Option<Arc<Tunnel>>,
+    metrics: Arc<Metrics>,
+    reply_channel_tx: Sender<SessionReply>,
     rx_socket: Arc<UdpSocket>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> io::Result<()> {
     let shared_reply_channel = Arc::new(reply_channel_tx);
     let sessions = Arc::new(RwLock::new(SessionCache::new()));
+    let shutdown_grace_period = Duration::from_secs(args.shutdown_grace_period);
+    let mut drain_deadline: Option<Instant> = None;
 
     loop {
         let mut buf = Vec::with_capacity(MAX_UDP_PACKET_SIZE.into());
-        match rx_socket.recv_buf_from(&mut buf).await {
+        let recv_result = match drain_deadline {
+            None => tokio::select! {
+                result = rx_socket.recv_buf_from(&mut buf) => result,
+                _ = shutdown.changed() => {
+                    info!(
+                        "Shutdown requested; no longer accepting new sessions, draining existing ones for up to {:?}",
+                        shutdown_grace_period
+                    );
+                    drain_deadline = Some(Instant::now() + shutdown_grace_period);
+                    continue;
+                }
+            },
+            Some(deadline) => {
+                match timeout(deadline.saturating_duration_since(Instant::now()), rx_socket.recv_buf_from(&mut buf)).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        info!("Shutdown grace period elapsed; no longer routing to existing sessions");
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        match recv_result {
             Err(err) => match handle_io_error(err) {
                 ErrorAction::Terminate(err) => return Err(err),
                 ErrorAction::Continue => continue,
             },
             Ok((_len, source)) => {
+                let buf = match &source_tunnel {
+                    Some(source_tunnel) => match source_tunnel.decrypt(&buf) {
+                        Ok(plaintext) => plaintext,
+                        Err(err) => {
+                            error!("Dropping unauthenticated tunnel datagram from {}: {:?}", source, err);
+                            continue;
+                        }
+                    },
+                    None => buf,
+                };
+
                 let mut session_cache = sessions.write().await;
-                let session_channel_tx = match session_cache.entry(source.into()) {
+                if drain_deadline.is_some() && !session_cache.contains_key(&source.into()) {
+                    // Shutting down: let already-active sessions drain, but don't create new ones.
+                    continue;
+                }
+                let (session_channel_tx, drop_counter) = match session_cache.entry(source.into()) {
                     Entry::Vacant(entry) => {
-                        info!("Creating a new session for {source}");
-                        let session = match Session::new(&args, source.into()).await {
+                        let backend = backend_pool.select(&source.into());
+                        info!("Creating a new session for {source}, assigned to backend {backend}");
+                        let session = match Session::new(
+                            &args,
+                            source.into(),
+                            backend,
+                            destination_tunnel.clone(),
+                            metrics.clone(),
+                        )
+                        .await
+                        {
                             Ok(created_session) => Arc::new(created_session),
                             Err(err) => {
                                 error!("Failed to create a session for {}: {:?}", source, err);
                                 continue;
                             }
                         };
+                        metrics.active_sessions.fetch_add(1, Ordering::Relaxed);
 
-                        let (tx, rx) = mpsc::unbounded_channel();
+                        let (tx, rx) = mpsc::channel(args.session_queue_size);
+                        let drop_counter = Arc::new(AtomicU64::new(0));
 
                         let tx_session = session.clone();
                         let tx_session_cache = sessions.clone();
+                        let tx_metrics = metrics.clone();
+                        let tx_shutdown = shutdown.clone();
                         tokio::spawn(async move {
-                            if let Err(err) = tx_session.tx_loop(rx, args.session_timeout).await {
-                                error!("TX error for {}: {:?}", source, err);
+                            let expired = match tx_session
+                                .tx_loop(rx, args.session_timeout, tx_shutdown, shutdown_grace_period)
+                                .await
+                            {
+                                Ok(expired) => expired,
+                                Err(err) => {
+                                    error!("TX error for {}: {:?}", source, err);
+                                    false
+                                }
+                            };
+                            if tx_session_cache.write().await.remove(&source.into()).is_some() {
+                                tx_metrics.active_sessions.fetch_sub(1, Ordering::Relaxed);
+                                if expired {
+                                    tx_metrics.sessions_expired.fetch_add(1, Ordering::Relaxed);
+                                }
                             }
-                            tx_session_cache.write().await.remove(&source.into());
                         });
 
                         let rx_session = session.clone();
                         let rx_session_cache = sessions.clone();
                         let my_reply_channel = shared_reply_channel.clone();
+                        let rx_metrics = metrics.clone();
+                        let rx_shutdown = shutdown.clone();
                         tokio::spawn(async move {
-                            if let Err(err) = rx_session
-                                .rx_loop(my_reply_channel, args.session_timeout)
+                            let expired = match rx_session
+                                .rx_loop(my_reply_channel, args.session_timeout, rx_shutdown, shutdown_grace_period)
                                 .await
                             {
-                                error!("RX error for {}: {:?}", source, err);
+                                Ok(expired) => expired,
+                                Err(err) => {
+                                    error!("RX error for {}: {:?}", source, err);
+                                    false
+                                }
+                            };
+                            if rx_session_cache.write().await.remove(&source.into()).is_some() {
+                                rx_metrics.active_sessions.fetch_sub(1, Ordering::Relaxed);
+                                if expired {
+                                    rx_metrics.sessions_expired.fetch_add(1, Ordering::Relaxed);
+                                }
                             }
-                            rx_session_cache.write().await.remove(&source.into());
                         });
 
-                        let (inserted_tx, _session) = entry.insert((tx, session));
-                        inserted_tx
+                        let (inserted_tx, _session, inserted_counter) =
+                            entry.insert((tx, session, drop_counter));
+                        (inserted_tx.clone(), inserted_counter.clone())
                     }
                     Entry::Occupied(entry) => {
-                        let (existing_tx, _session) = entry.into_mut();
-                        existing_tx
+                        let (existing_tx, _session, existing_counter) = entry.into_mut();
+                        (existing_tx.clone(), existing_counter.clone())
                     }
                 };
+                // Drop the cache lock before sending: on a closed channel we need to re-acquire
+                // it below to remove the session, and the lock is not reentrant.
+                drop(session_cache);
 
-                if session_channel_tx.send(buf).is_err() {
-                    error!(
-                        "Dropped packet for {} because its proxy session is closed",
-                        source
-                    );
-                    sessions.write().await.remove(&source.into());
+                match session_channel_tx.try_send(buf) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        metrics.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                        let drops = drop_counter.fetch_add(1, Ordering::Relaxed) + 1;
+                        if drops % DROP_LOG_INTERVAL == 0 {
+                            warn!(
+                                "Dropped {} datagrams for {} because its session queue is full",
+                                drops, source
+                            );
+                        }
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        metrics.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                        error!(
+                            "Dropped packet for {} because its proxy session is closed",
+                            source
+                        );
+                        if sessions.write().await.remove(&source.into()).is_some() {
+                            metrics.active_sessions.fetch_sub(1, Ordering::Relaxed);
+                        }
+                    }
                 }
             }
         };
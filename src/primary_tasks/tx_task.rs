@@ -1,27 +1,46 @@
-use std::{io, sync::Arc};
+use std::{
+    io,
+    sync::{atomic::Ordering, Arc},
+};
 
-use tokio::{net::UdpSocket, sync::mpsc::UnboundedReceiver};
+use tokio::{net::UdpSocket, sync::mpsc::Receiver};
 
 use crate::{
     error_util::{handle_io_error, ErrorAction},
+    metrics::Metrics,
     session::SessionReply,
+    tunnel::Tunnel,
 };
 
 /// Loops infinitely over the `tx_socket` to forward traffic from the destination of the proxy.
 ///
 /// This task recives channel messages representing responses from the proxy destination over
 /// `reply_channel_tx` from [crate::session::Session]s and sends them back to the original
-/// source via `tx_socket`.
+/// source via `tx_socket`. If `source_tunnel` is set, replies are encrypted before being sent.
 pub async fn tx_loop(
-    mut reply_channel_rx: UnboundedReceiver<SessionReply>,
+    mut reply_channel_rx: Receiver<SessionReply>,
     tx_socket: Arc<UdpSocket>,
+    source_tunnel: Option<Arc<Tunnel>>,
+    metrics: Arc<Metrics>,
 ) -> io::Result<()> {
     while let Some(reply) = reply_channel_rx.recv().await {
+        let payload_len = reply.data.len();
+        let data = match &source_tunnel {
+            Some(source_tunnel) => source_tunnel.encrypt(&reply.data),
+            None => reply.data,
+        };
+
         match tx_socket
-            .send_to(&reply.data, (reply.source.address, reply.source.port))
+            .send_to(&data, (reply.source.address, reply.source.port))
             .await
         {
-            Ok(_) => continue,
+            Ok(_) => {
+                metrics.packets_forwarded_to_client.fetch_add(1, Ordering::Relaxed);
+                metrics
+                    .bytes_forwarded_to_client
+                    .fetch_add(payload_len as u64, Ordering::Relaxed);
+                continue;
+            }
             Err(err) => match handle_io_error(err) {
                 ErrorAction::Terminate(err) => return Err::<(), io::Error>(err),
                 ErrorAction::Continue => continue,
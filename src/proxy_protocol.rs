@@ -0,0 +1,80 @@
+use std::net::{IpAddr, SocketAddr};
+
+/// The fixed 12-byte signature that opens every PROXY protocol v2 header.
+const SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+/// Version 2, command PROXY (as opposed to LOCAL).
+const VERSION_COMMAND: u8 = 0x21;
+const PROTOCOL_UDP_IPV4: u8 = 0x12;
+const PROTOCOL_UDP_IPV6: u8 = 0x22;
+
+/// Builds a binary PROXY protocol v2 header describing a UDP datagram flowing from `src` to
+/// `dst`, so a backend behind this proxy can recover the original client address instead of
+/// only ever seeing this proxy's address. Callers prepend the returned bytes to the datagram.
+pub fn build_header(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (protocol, addresses) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut addresses = Vec::with_capacity(12);
+            addresses.extend_from_slice(&src_ip.octets());
+            addresses.extend_from_slice(&dst_ip.octets());
+            (PROTOCOL_UDP_IPV4, addresses)
+        }
+        (src_ip, dst_ip) => {
+            // Mixed IPv4/IPv6 pairs are mapped into IPv6 so both addresses share one family,
+            // since the v2 header can only describe a single address family per header.
+            let mut addresses = Vec::with_capacity(32);
+            addresses.extend_from_slice(&to_v6_octets(src_ip));
+            addresses.extend_from_slice(&to_v6_octets(dst_ip));
+            (PROTOCOL_UDP_IPV6, addresses)
+        }
+    };
+
+    let mut header = Vec::with_capacity(SIGNATURE.len() + 4 + addresses.len() + 4);
+    header.extend_from_slice(&SIGNATURE);
+    header.push(VERSION_COMMAND);
+    header.push(protocol);
+    header.extend_from_slice(&((addresses.len() + 4) as u16).to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header.extend_from_slice(&src.port().to_be_bytes());
+    header.extend_from_slice(&dst.port().to_be_bytes());
+    header
+}
+
+fn to_v6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn builds_ipv4_header_with_expected_length_and_prefix() {
+        let src = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 1234);
+        let dst = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 9000);
+
+        let header = build_header(src, dst);
+
+        assert_eq!(&header[0..12], &SIGNATURE);
+        assert_eq!(header[12], VERSION_COMMAND);
+        assert_eq!(header[13], PROTOCOL_UDP_IPV4);
+        assert_eq!(header.len(), 16 + 12);
+    }
+
+    #[test]
+    fn builds_ipv6_header_when_either_address_is_v6() {
+        let src = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 1234);
+        let dst = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 9000);
+
+        let header = build_header(src, dst);
+
+        assert_eq!(header[13], PROTOCOL_UDP_IPV6);
+        assert_eq!(header.len(), 16 + 36);
+    }
+}
@@ -2,19 +2,28 @@ use std::{
     fmt::{self, Display, Formatter},
     io::{self, ErrorKind},
     net::{IpAddr, SocketAddr},
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use log::info;
+use log::{error, info, warn};
 use tokio::{
     net::UdpSocket,
-    sync::mpsc::{UnboundedReceiver, UnboundedSender},
-    time::timeout,
+    sync::{
+        mpsc::{error::TrySendError, Receiver, Sender},
+        watch, RwLock,
+    },
+    time::{sleep, timeout},
 };
 
 use crate::{
     error_util::{handle_io_error, ErrorAction},
+    metrics::Metrics,
+    proxy_protocol,
+    tunnel::Tunnel,
     Args, MAX_UDP_PACKET_SIZE,
 };
 
@@ -31,10 +40,43 @@ pub struct SessionSource {
 pub struct Session {
     /// The source that this session is receiving traffic from
     source: SessionSource,
-    /// The socket that this session is using to communicate with the destination
-    destination: Arc<UdpSocket>,
+    /// The backend this session was assigned to by the [crate::backend::BackendPool]
+    backend: SocketAddr,
+    /// The socket that this session is using to communicate with the destination. Held behind a
+    /// lock so [Self::reconnect] can rebind it without requiring `&mut self` in [Self::tx_loop]/[Self::rx_loop]
+    destination: RwLock<Arc<UdpSocket>>,
+    /// The address [Self::destination] is bound to, retained so it can be rebound on reconnect
+    source_address: IpAddr,
+    /// Whether a PROXY protocol v2 header should be prepended to datagrams sent to [Self::destination]
+    proxy_protocol: bool,
+    /// The tunnel used to encrypt datagrams sent to, and decrypt datagrams received from, [Self::destination].
+    /// Independent of the source-facing tunnel used in [crate::primary_tasks]
+    destination_tunnel: Option<Arc<Tunnel>>,
+    /// Count of replies dropped because `reply_channel` was full, logged periodically in [Self::rx_loop]
+    reply_drops: AtomicU64,
+    /// Count of consecutive connection-refused errors seen by [Self::tx_loop]/[Self::rx_loop],
+    /// reset on any successful send or receive; used to decide when to reconnect
+    consecutive_failures: AtomicU64,
+    /// Guards [Self::reconnect_with_backoff] so only one of [Self::tx_loop]/[Self::rx_loop] rebinds
+    /// [Self::destination] at a time; the other simply picks up the new socket once it lands
+    reconnecting: AtomicBool,
+    /// Shared counters this session reports traffic and timeout events to
+    metrics: Arc<Metrics>,
 }
 
+/// How many drops are accumulated on a queue before another warning is logged for it, so a
+/// sustained flood doesn't spam the log once per dropped datagram.
+const DROP_LOG_INTERVAL: u64 = 100;
+
+/// How many consecutive connection-refused errors are tolerated before [Session::destination] is
+/// rebound, in case the backend is behind a restarting service or its address has changed.
+const RECONNECT_AFTER_CONSECUTIVE_REFUSALS: u64 = 3;
+
+/// Delay before retrying after the first failed reconnect attempt (the first attempt itself is
+/// made at once); doubles on each subsequent failed attempt up to [RECONNECT_MAX_DELAY].
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(200);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 pub struct SessionReply {
     pub source: SessionSource,
@@ -49,37 +91,170 @@ impl SessionReply {
 
 impl Session {
     /// Establish a new session that binds to an [Args::source_address] and establishes
-    /// a connection to [Args::destination_address] on [Args::destination_port]. Returns an [io::Error]
-    /// if the connection fails to establish.
-    pub async fn new(args: &Args, source: SessionSource) -> io::Result<Self> {
+    /// a connection to the given `backend`, as chosen by the [crate::backend::BackendPool].
+    /// Returns an [io::Error] if the connection fails to establish.
+    pub async fn new(
+        args: &Args,
+        source: SessionSource,
+        backend: SocketAddr,
+        destination_tunnel: Option<Arc<Tunnel>>,
+        metrics: Arc<Metrics>,
+    ) -> io::Result<Self> {
         // Let the OS assign us an available port
-        let destination = Arc::new(UdpSocket::bind((args.source_address, 0)).await?);
+        let destination = UdpSocket::bind((args.source_address, 0)).await?;
         // Connect to the destination
-        destination
-            .connect((args.destination_address, args.destination_port))
-            .await?;
+        destination.connect(backend).await?;
 
         Ok(Session {
             source,
-            destination,
+            backend,
+            destination: RwLock::new(Arc::new(destination)),
+            source_address: args.source_address,
+            proxy_protocol: args.proxy_protocol,
+            destination_tunnel,
+            reply_drops: AtomicU64::new(0),
+            consecutive_failures: AtomicU64::new(0),
+            reconnecting: AtomicBool::new(false),
+            metrics,
         })
     }
 
+    /// Rebinds [Self::destination] to a fresh socket connected to [Self::backend].
+    async fn reconnect(&self) -> io::Result<()> {
+        let new_destination = UdpSocket::bind((self.source_address, 0)).await?;
+        new_destination.connect(self.backend).await?;
+        *self.destination.write().await = Arc::new(new_destination);
+        Ok(())
+    }
+
+    /// Rebinds [Self::destination] with exponential backoff between failed attempts, giving up and
+    /// returning the last error once `deadline` has passed. Only a failed local bind or connect
+    /// counts as a failed attempt: connecting a UDP socket does not contact the backend, so this
+    /// returns `Ok` on the first attempt even while the backend is still down.
+    async fn reconnect_with_backoff(&self, deadline: Instant) -> io::Result<()> {
+        let mut delay = RECONNECT_BASE_DELAY;
+        loop {
+            match self.reconnect().await {
+                Ok(()) => {
+                    info!("Reconnected session for {} to backend {}", self.source, self.backend);
+                    self.consecutive_failures.store(0, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Err(err) if Instant::now() < deadline => {
+                    warn!(
+                        "Failed to reconnect session for {} to backend {}, retrying in {:?}: {:?}",
+                        self.source, self.backend, delay, err
+                    );
+                    sleep(delay).await;
+                    delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Records a connection-refused error and, once enough of them have accumulated consecutively,
+    /// reconnects [Self::destination]. [Self::tx_loop] and [Self::rx_loop] can both observe
+    /// refusals and cross the threshold around the same time, so [Self::reconnecting] ensures only
+    /// one of them actually drives the reconnect; the other just leaves its failure counted and
+    /// picks up the new [Self::destination] once the in-progress attempt finishes.
+    async fn note_failure_and_maybe_reconnect(&self, deadline: Instant) -> io::Result<()> {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures % RECONNECT_AFTER_CONSECUTIVE_REFUSALS != 0 {
+            return Ok(());
+        }
+        if self
+            .reconnecting
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Ok(());
+        }
+        let result = self.reconnect_with_backoff(deadline).await;
+        self.reconnecting.store(false, Ordering::Release);
+        result
+    }
+
     /// Loops indefinitely waiting for messages on `source_channel` and send them to the [Self::destination].
-    /// Ends the loop if no message is recieved for `session_timeout` seconds or any unrecoverable
-    /// error occurs in transmission.
+    /// Ends the loop if no message is recieved for `session_timeout` seconds or any unrecoverable error
+    /// occurs in transmission. Once `shutdown` is signaled, already-queued messages keep draining for
+    /// up to `shutdown_grace_period` before the loop ends, rather than being dropped immediately. Returns
+    /// whether the loop ended because the session actually timed out, so callers can dedupe
+    /// [Metrics::sessions_expired] against the sibling [Self::rx_loop] instead of both loops counting it.
     pub async fn tx_loop(
         &self,
-        mut source_channel: UnboundedReceiver<Vec<u8>>,
+        mut source_channel: Receiver<Vec<u8>>,
         session_timeout: u64,
-    ) -> io::Result<()> {
+        mut shutdown: watch::Receiver<bool>,
+        shutdown_grace_period: Duration,
+    ) -> io::Result<bool> {
         let duration = Duration::from_secs(session_timeout);
-        while let Ok(Some(data)) = timeout(duration, source_channel.recv()).await {
-            match self.destination.send(&data).await {
-                Ok(_) => {}
+        let mut drain_deadline: Option<Instant> = None;
+        let mut expired = false;
+        loop {
+            let wait = match drain_deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => duration,
+            };
+            let data = tokio::select! {
+                result = timeout(wait, source_channel.recv()) => match result {
+                    Ok(Some(data)) => data,
+                    Ok(None) => break,
+                    Err(_) => {
+                        if drain_deadline.is_some() {
+                            info!("Finished draining tx session for {} within the shutdown grace period", self.source);
+                        } else {
+                            expired = true;
+                            info!("Session for {} timed out waiting for source traffic", self.source);
+                        }
+                        break;
+                    }
+                },
+                _ = shutdown.changed(), if drain_deadline.is_none() => {
+                    info!(
+                        "Shutdown requested; draining tx session for {} for up to {:?}",
+                        self.source, shutdown_grace_period
+                    );
+                    drain_deadline = Some(Instant::now() + shutdown_grace_period);
+                    continue;
+                }
+            };
+
+            let payload_len = data.len();
+            let packet = if self.proxy_protocol {
+                let mut framed = proxy_protocol::build_header(
+                    SocketAddr::new(self.source.address, self.source.port),
+                    self.backend,
+                );
+                framed.extend_from_slice(&data);
+                framed
+            } else {
+                data
+            };
+            let packet = match &self.destination_tunnel {
+                Some(destination_tunnel) => destination_tunnel.encrypt(&packet),
+                None => packet,
+            };
+
+            let destination = self.destination.read().await.clone();
+            match destination.send(&packet).await {
+                Ok(_) => {
+                    self.consecutive_failures.store(0, Ordering::Relaxed);
+                    self.metrics.packets_forwarded_to_backend.fetch_add(1, Ordering::Relaxed);
+                    self.metrics
+                        .bytes_forwarded_to_backend
+                        .fetch_add(payload_len as u64, Ordering::Relaxed);
+                }
                 Err(err) => match err.kind() {
-                    // Destination service hasn't started yet
-                    ErrorKind::ConnectionRefused => {}
+                    // Destination service hasn't started yet, or has stopped responding; reconnect
+                    // once enough consecutive refusals have accumulated
+                    ErrorKind::ConnectionRefused => {
+                        let deadline = Instant::now() + duration;
+                        if let Err(err) = self.note_failure_and_maybe_reconnect(deadline).await {
+                            error!("Closing session for {} after backend stayed down: {:?}", self.source, err);
+                            return Err(err);
+                        }
+                    }
                     _ => match handle_io_error(err) {
                         ErrorAction::Terminate(cause) => return Err(cause),
                         ErrorAction::Continue => {}
@@ -88,43 +263,103 @@ impl Session {
             }
         }
         info!("Closing tx session for {}", self.source);
-        Ok(())
+        Ok(expired)
     }
 
     /// Loops indefinitely waiting for replies from the [Self::destination] and forwards them to the `reply_channel`.
-    /// Ends the loop if no reply is recieved for `session_timeout` seconds.
+    /// Ends the loop if no reply is recieved for `session_timeout` seconds. Once `shutdown` is signaled,
+    /// replies keep draining for up to `shutdown_grace_period` before the loop ends, rather than being
+    /// dropped immediately. Returns whether the loop ended because the session actually timed out, so
+    /// callers can dedupe [Metrics::sessions_expired] against the sibling [Self::tx_loop] instead of both
+    /// loops counting it.
     pub async fn rx_loop(
         &self,
-        reply_channel: Arc<UnboundedSender<SessionReply>>,
+        reply_channel: Arc<Sender<SessionReply>>,
         session_timeout: u64,
-    ) -> io::Result<()> {
+        mut shutdown: watch::Receiver<bool>,
+        shutdown_grace_period: Duration,
+    ) -> io::Result<bool> {
         let duration = Duration::from_secs(session_timeout);
+        let mut drain_deadline: Option<Instant> = None;
         loop {
+            let wait = match drain_deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => duration,
+            };
             let mut buf = Vec::with_capacity(MAX_UDP_PACKET_SIZE.into());
-            match timeout(duration, self.destination.recv_buf(&mut buf)).await {
-                Ok(result) => {
-                    if let Err(err) = result {
-                        match handle_io_error(err) {
+            let destination = self.destination.read().await.clone();
+            tokio::select! {
+                result = timeout(wait, destination.recv_buf(&mut buf)) => match result {
+                    Ok(Ok(_)) => {
+                        self.consecutive_failures.store(0, Ordering::Relaxed);
+                    }
+                    Ok(Err(err)) => match err.kind() {
+                        // The backend has stopped responding; reconnect once enough consecutive
+                        // refusals have accumulated
+                        ErrorKind::ConnectionRefused => {
+                            let deadline = Instant::now() + duration;
+                            if let Err(err) = self.note_failure_and_maybe_reconnect(deadline).await {
+                                error!("Closing session for {} after backend stayed down: {:?}", self.source, err);
+                                return Err(err);
+                            }
+                            continue;
+                        }
+                        _ => match handle_io_error(err) {
                             ErrorAction::Terminate(cause) => return Err(cause),
                             ErrorAction::Continue => {}
+                        },
+                    },
+                    Err(_) => {
+                        if drain_deadline.is_some() {
+                            info!("Finished draining rx session for {} within the shutdown grace period", self.source);
+                            return Ok(false);
+                        } else {
+                            info!("Closing rx session for {}", self.source);
+                            return Ok(true);
                         }
                     }
-                }
-                Err(_) => {
-                    info!("Closing rx session for {}", self.source);
-                    return Ok(());
+                },
+                _ = shutdown.changed(), if drain_deadline.is_none() => {
+                    info!(
+                        "Shutdown requested; draining rx session for {} for up to {:?}",
+                        self.source, shutdown_grace_period
+                    );
+                    drain_deadline = Some(Instant::now() + shutdown_grace_period);
+                    continue;
                 }
             };
 
-            if reply_channel
-                .send(SessionReply::new(self.source, buf))
-                .is_err()
-            {
-                return Err(io::Error::new(
-                    ErrorKind::ConnectionAborted,
-                    "Primary tx task has stopped listening, dropping reply as the proxy will soon terminate"
-                ));
+            let buf = match &self.destination_tunnel {
+                Some(destination_tunnel) => match destination_tunnel.decrypt(&buf) {
+                    Ok(plaintext) => plaintext,
+                    Err(err) => {
+                        error!("Dropping unauthenticated tunnel datagram for {}: {:?}", self.source, err);
+                        continue;
+                    }
+                },
+                None => buf,
             };
+
+            match reply_channel.try_send(SessionReply::new(self.source, buf)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    self.metrics.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                    let drops = self.reply_drops.fetch_add(1, Ordering::Relaxed) + 1;
+                    if drops % DROP_LOG_INTERVAL == 0 {
+                        warn!(
+                            "Dropped {} replies for {} because the reply channel is full",
+                            drops, self.source
+                        );
+                    }
+                }
+                Err(TrySendError::Closed(_)) => {
+                    self.metrics.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        "Primary tx task has stopped listening, dropping reply as the proxy will soon terminate"
+                    ));
+                }
+            }
         }
     }
 }
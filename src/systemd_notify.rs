@@ -0,0 +1,38 @@
+use std::{
+    env, io,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    time::Duration,
+};
+
+/// Sends a state notification to systemd via the datagram socket named in the `NOTIFY_SOCKET`
+/// environment variable, per sd_notify(3). A no-op when the variable is unset, e.g. when the
+/// proxy wasn't started by systemd such as during local development.
+pub fn notify(state: &str) -> io::Result<()> {
+    let Some(socket_path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    let address = match socket_path.as_bytes().strip_prefix(b"@") {
+        // A leading '@' names an abstract-namespace socket; sd_notify(3) requires clients to
+        // swap it for the NUL byte the kernel actually binds on.
+        Some(abstract_name) => SocketAddr::from_abstract_name(abstract_name)?,
+        None => SocketAddr::from_pathname(socket_path)?,
+    };
+    socket.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// Parses the `WATCHDOG_USEC` environment variable set by systemd for `WatchdogSec=`-enabled
+/// units, returning the interval at which `WATCHDOG=1` pings should be sent, which is half of
+/// the configured timeout per systemd convention. Returns `None` if no watchdog is configured.
+pub fn watchdog_interval() -> Option<Duration> {
+    let watchdog_usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(watchdog_usec) / 2)
+}
@@ -0,0 +1,112 @@
+use std::{env, fs, io, os::unix::ffi::OsStrExt, path::PathBuf};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, OsRng},
+    Key, KeyInit, XChaCha20Poly1305, XNonce,
+};
+
+/// Environment variable consulted for the destination-leg tunnel's pre-shared key when
+/// `--destination-tunnel-key-file` is not set. Encrypts/decrypts traffic to/from the backend.
+pub const DESTINATION_TUNNEL_KEY_ENV_VAR: &str = "UDP_PROXY_DESTINATION_TUNNEL_KEY";
+/// Environment variable consulted for the source-leg tunnel's pre-shared key when
+/// `--source-tunnel-key-file` is not set. Encrypts/decrypts traffic to/from the original client.
+pub const SOURCE_TUNNEL_KEY_ENV_VAR: &str = "UDP_PROXY_SOURCE_TUNNEL_KEY";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+/// Encrypts and authenticates datagrams with XChaCha20-Poly1305 under a single pre-shared key, so
+/// traffic between two instances of this proxy can cross an untrusted network. Frames are laid
+/// out on the wire as `nonce (24 bytes) || ciphertext || tag`.
+pub struct Tunnel {
+    cipher: XChaCha20Poly1305,
+}
+
+impl Tunnel {
+    fn new(key: &[u8; KEY_LEN]) -> Self {
+        Tunnel {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// Loads the pre-shared key from `key_file` if given, otherwise from `env_var`
+    /// ([DESTINATION_TUNNEL_KEY_ENV_VAR] or [SOURCE_TUNNEL_KEY_ENV_VAR]). Returns `Ok(None)` if
+    /// neither is set, meaning tunnel mode is disabled for that leg. Returns an [io::Error] if the
+    /// key cannot be read or is not exactly [KEY_LEN] bytes.
+    pub fn from_args(key_file: &Option<PathBuf>, env_var: &str) -> io::Result<Option<Self>> {
+        let raw_key = match key_file {
+            Some(path) => fs::read(path)?,
+            None => match env::var_os(env_var) {
+                // A pre-shared key is arbitrary bytes, not necessarily valid UTF-8, so the raw
+                // OS string must be taken as-is rather than going through `env::var`.
+                Some(value) => value.as_bytes().to_vec(),
+                None => return Ok(None),
+            },
+        };
+
+        let key: [u8; KEY_LEN] = raw_key.try_into().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Tunnel key must be exactly {KEY_LEN} bytes"),
+            )
+        })?;
+
+        Ok(Some(Tunnel::new(&key)))
+    }
+
+    /// Encrypts `plaintext` under a freshly generated random nonce, returning `nonce || ciphertext || tag`.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .expect("encryption with a valid key/nonce cannot fail");
+
+        let mut framed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        framed.extend_from_slice(&nonce);
+        framed.append(&mut ciphertext);
+        framed
+    }
+
+    /// Splits the nonce off of `framed` and decrypts/authenticates the remainder. Returns an
+    /// [io::Error] if the frame is too short to contain a nonce or authentication fails.
+    pub fn decrypt(&self, framed: &[u8]) -> io::Result<Vec<u8>> {
+        if framed.len() < NONCE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Tunnel frame is shorter than a nonce",
+            ));
+        }
+
+        let (nonce, ciphertext) = framed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Tunnel authentication failed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decrypts_its_own_ciphertext_back_to_the_original_plaintext() {
+        let tunnel = Tunnel::new(&[7u8; KEY_LEN]);
+        let plaintext = b"hello from the other side of the tunnel";
+
+        let framed = tunnel.encrypt(plaintext);
+        let decrypted = tunnel.decrypt(&framed).expect("roundtrip should succeed");
+
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn rejects_a_tampered_or_too_short_frame() {
+        let tunnel = Tunnel::new(&[7u8; KEY_LEN]);
+        let mut framed = tunnel.encrypt(b"authenticate me");
+        let last = framed.len() - 1;
+        framed[last] ^= 0xFF;
+
+        assert!(tunnel.decrypt(&framed).is_err());
+        assert!(tunnel.decrypt(&framed[..NONCE_LEN - 1]).is_err());
+    }
+}